@@ -1,14 +1,23 @@
 // Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 use dashmap::DashMap;
 use futures::future::join_all;
+use siphasher::sip::SipHasher;
 
 use super::{standalone::StandaloneImpl, DbClient};
 use crate::{
-    errors::{should_refresh, ClusterWriteError},
+    errors::ClusterWriteError,
     model::{
         request::QueryRequest,
         route::Endpoint,
@@ -20,10 +29,88 @@ use crate::{
     Error, Result,
 };
 
+/// Default interval of the background reaper scanning the standalone pool.
+const DEFAULT_POOL_GC_INTERVAL: Duration = Duration::from_secs(60);
+/// Default idle duration after which an unused channel becomes evictable.
+const DEFAULT_POOL_MAX_IDLE: Duration = Duration::from_secs(10 * 60);
+/// Default upper bound on the number of cached standalone channels.
+const DEFAULT_POOL_MAX_SIZE: usize = 1024;
+
+/// Tunables controlling the lifetime of cached standalone channels.
+#[derive(Debug, Clone)]
+pub struct StandalonePoolConfig {
+    /// Channels idle for longer than this are reaped in the background.
+    pub max_idle: Duration,
+    /// Upper bound on the number of cached channels, enforced with LRU eviction
+    /// of idle channels. The pool can exceed it while every cached channel is
+    /// in flight.
+    pub max_pool_size: usize,
+    /// How often the background reaper scans for idle channels.
+    pub gc_interval: Duration,
+}
+
+impl Default for StandalonePoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: DEFAULT_POOL_MAX_IDLE,
+            max_pool_size: DEFAULT_POOL_MAX_SIZE,
+            gc_interval: DEFAULT_POOL_GC_INTERVAL,
+        }
+    }
+}
+
+/// Default number of re-route retries performed on a refreshable error.
+const DEFAULT_MAX_RETRIES: usize = 2;
+/// Default backoff applied before the first retry.
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Default ceiling for the exponential backoff between retries.
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Tunables controlling re-route and retry on refreshable server errors.
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// Maximum number of retries after the initial attempt.
+    pub max_retries: usize,
+    /// Backoff before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound for the exponentially growing backoff.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
 /// Client for ceresdb of cluster mode.
 pub struct ClusterImpl<R: Router> {
     router: R,
     standalone_pool: StandalonePool,
+    retry_config: RetryConfig,
+    /// Monotonic counter rotating endpoint selection so successive requests for
+    /// the same metric spread across its replica candidates.
+    rotation: AtomicU64,
+}
+
+/// Deterministically pick one endpoint out of a metric's replica candidates.
+///
+/// Uses SipHash of the metric name combined with a per-request rotation so a
+/// single metric's traffic is balanced across replicas rather than pinned to
+/// one node, while a given (metric, rotation) pair always resolves the same way.
+/// Returns `None` when there are no candidates.
+fn select_endpoint(candidates: &[Endpoint], metric: &str, rotation: u64) -> Option<Endpoint> {
+    if candidates.is_empty() {
+        return None;
+    }
+    let mut hasher = SipHasher::new();
+    metric.hash(&mut hasher);
+    let slot = hasher.finish().wrapping_add(rotation) % candidates.len() as u64;
+    Some(candidates[slot as usize].clone())
 }
 
 #[async_trait]
@@ -35,41 +122,127 @@ impl<R: Router> DbClient for ClusterImpl<R> {
             ));
         }
 
-        let endpoint = match self.router.route(&req.metrics, ctx).await {
-            Ok(mut eps) => {
-                if let Some(ep) = eps[0].take() {
-                    ep
-                } else {
-                    return Err(Error::Unknown(
-                        "Metric doesn't have corresponding endpoint".to_string(),
-                    ));
+        let mut backoff = self.retry_config.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            // Bumping the rotation every attempt also serves as the fallback to
+            // the next candidate once the previous one has been evicted.
+            let rotation = self.rotation.fetch_add(1, Ordering::Relaxed);
+            let endpoint = match self.router.route(&req.metrics, ctx).await {
+                Ok(candidates) => {
+                    match select_endpoint(&candidates[0], &req.metrics[0], rotation) {
+                        Some(ep) => ep,
+                        None => {
+                            return Err(Error::Unknown(
+                                "Metric doesn't have corresponding endpoint".to_string(),
+                            ));
+                        }
+                    }
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+
+            let clnt = self.standalone_pool.get_or_create(&endpoint).clone();
+
+            match clnt.query_internal(ctx, req.clone()).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) => {
+                    // On a refreshable error, evict the stale route and retry
+                    // against freshly resolved endpoints instead of making the
+                    // caller pay the re-route cost on its next call.
+                    if !e.is_refreshable() {
+                        return Err(e);
+                    }
+                    self.router.evict(&req.metrics);
+                    if attempt >= self.retry_config.max_retries {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.retry_config.max_backoff);
+                }
+            }
+        }
+    }
+
+    async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+        // Metrics still to be (re)attempted; starts as the full request.
+        let mut pending: Vec<String> = req.write_entries.iter().map(|(m, _)| m.clone()).collect();
+        // Accumulates the final outcome of metrics that are done (succeeded or
+        // failed non-refreshably, or exhausted their retries).
+        let mut settled: Vec<(Vec<String>, Result<WriteResponse>)> = Vec::new();
+
+        let mut backoff = self.retry_config.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let sub_req = sub_write_request(req, &pending);
+            let round = self.write_once(ctx, &sub_req).await;
+
+            let last_round = attempt >= self.retry_config.max_retries;
+            let mut evicts = Vec::new();
+            let mut retry_metrics = Vec::new();
+            for (metrics, result) in round {
+                if matches!(&result, Err(e) if e.is_refreshable()) {
+                    evicts.extend(metrics.iter().cloned());
+                    if !last_round {
+                        // Only the failed partition is retried; partitions that
+                        // already succeeded are never re-sent.
+                        retry_metrics.extend(metrics);
+                        continue;
+                    }
                 }
+                settled.push((metrics, result));
             }
-            Err(e) => {
-                return Err(e);
+
+            if !evicts.is_empty() {
+                self.router.evict(&evicts);
+            }
+            if retry_metrics.is_empty() || last_round {
+                break;
             }
-        };
 
-        let clnt = self.standalone_pool.get_or_create(&endpoint).clone();
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.retry_config.max_backoff);
+            pending = retry_metrics;
+        }
 
-        clnt.query_internal(ctx, req.clone()).await.map_err(|e| {
-            self.router.evict(&req.metrics);
-            e
-        })
+        let cluster_error: ClusterWriteError = settled.into();
+        if cluster_error.all_ok() {
+            Ok(cluster_error.ok.1)
+        } else {
+            Err(Error::ClusterWriteError(cluster_error))
+        }
     }
+}
 
-    async fn write(&self, ctx: &RpcContext, req: &WriteRequest) -> Result<WriteResponse> {
+impl<R: Router> ClusterImpl<R> {
+    /// Route, partition and send `req` exactly once, returning the per-partition
+    /// results paired with the metrics they cover (including metrics that had no
+    /// corresponding endpoint).
+    async fn write_once(
+        &self,
+        ctx: &RpcContext,
+        req: &WriteRequest,
+    ) -> Vec<(Vec<String>, Result<WriteResponse>)> {
         // Get metrics' related endpoints(some may not exist).
         let should_routes: Vec<_> = req.write_entries.iter().map(|(m, _)| m.clone()).collect();
-        let endpoints = self.router.route(&should_routes, ctx).await?;
+        let endpoints = match self.router.route(&should_routes, ctx).await {
+            Ok(endpoints) => endpoints,
+            Err(e) => return vec![(should_routes, Err(e))],
+        };
 
-        // Partition write entries in request according to related endpoints.
+        // Partition write entries in request according to related endpoints,
+        // balancing each metric across its replica candidates.
+        let rotation = self.rotation.fetch_add(1, Ordering::Relaxed);
         let mut no_corresponding_endpoints = Vec::new();
         let mut partition_by_endpoint = HashMap::new();
         endpoints
             .into_iter()
             .zip(should_routes.into_iter())
-            .for_each(|(ep, m)| match ep {
+            .for_each(|(candidates, m)| match select_endpoint(&candidates, &m, rotation) {
                 Some(ep) => {
                     let write_req = partition_by_endpoint
                         .entry(ep)
@@ -107,74 +280,279 @@ impl<R: Router> DbClient for ClusterImpl<R> {
             .zip(wirte_metrics.into_iter())
             .map(|(results, metrics)| (metrics, results))
             .collect();
-        metrics_result_pairs.push((
-            no_corresponding_endpoints,
-            Err(Error::Unknown(
-                "Metrics don't have corresponding endpoints".to_string(),
-            )),
-        ));
-
-        // Process results:
-        //  + Evict outdated endpoints.
-        //  + Merge results and return.
-        let evicts: Vec<_> = metrics_result_pairs
-            .iter()
-            .filter_map(|(metrics, result)| {
-                if let Err(Error::Server(serv_err)) = &result &&
-                should_refresh(serv_err.code, &serv_err.msg) {
-                Some(metrics.clone())
-            } else {
-                None
-            }
-            })
-            .flatten()
-            .collect();
-        self.router.evict(&evicts);
+        if !no_corresponding_endpoints.is_empty() {
+            metrics_result_pairs.push((
+                no_corresponding_endpoints,
+                Err(Error::Unknown(
+                    "Metrics don't have corresponding endpoints".to_string(),
+                )),
+            ));
+        }
 
-        let cluster_error: ClusterWriteError = metrics_result_pairs.into();
-        if cluster_error.all_ok() {
-            Ok(cluster_error.ok.1)
-        } else {
-            Err(Error::ClusterWriteError(cluster_error))
+        metrics_result_pairs
+    }
+}
+
+/// Build a `WriteRequest` containing only the entries for `metrics`, cloning
+/// them out of `original`. Metrics absent from `original` are skipped.
+fn sub_write_request(original: &WriteRequest, metrics: &[String]) -> WriteRequest {
+    let mut sub = WriteRequest::default();
+    for m in metrics {
+        if let Some(entry) = original.write_entries.get(m.as_str()) {
+            sub.write_entries.insert(m.clone(), entry.clone());
         }
     }
+    sub
 }
 
 impl<R: Router> ClusterImpl<R> {
+    /// Build a cluster client with default pool and retry config.
+    ///
+    /// Must be called from within a Tokio runtime: it spawns the pool's
+    /// background reaper via [`tokio::spawn`], which panics otherwise.
     pub fn new(route_client: R, standalone_buidler: RpcClientImplBuilder) -> Self {
+        Self::with_pool_config(route_client, standalone_buidler, StandalonePoolConfig::default())
+    }
+
+    /// Like [`ClusterImpl::new`] but with a custom pool config. Must also be
+    /// called from within a Tokio runtime (see [`ClusterImpl::new`]).
+    pub fn with_pool_config(
+        route_client: R,
+        standalone_buidler: RpcClientImplBuilder,
+        pool_config: StandalonePoolConfig,
+    ) -> Self {
+        Self::with_config(
+            route_client,
+            standalone_buidler,
+            pool_config,
+            RetryConfig::default(),
+        )
+    }
+
+    /// Like [`ClusterImpl::new`] but with custom pool and retry config. Must
+    /// also be called from within a Tokio runtime (see [`ClusterImpl::new`]).
+    pub fn with_config(
+        route_client: R,
+        standalone_buidler: RpcClientImplBuilder,
+        pool_config: StandalonePoolConfig,
+        retry_config: RetryConfig,
+    ) -> Self {
         Self {
             router: route_client,
-            standalone_pool: StandalonePool::new(standalone_buidler),
+            standalone_pool: StandalonePool::new(standalone_buidler, pool_config),
+            retry_config,
+            rotation: AtomicU64::new(0),
         }
     }
 }
 
+/// A cached standalone channel together with the stamp of its last use.
+///
+/// The stamp is stored as the number of milliseconds elapsed since the pool
+/// was created, which keeps it cheap to update atomically on the hot path.
+struct PooledStandalone {
+    client: Arc<StandaloneImpl<RpcClientImpl>>,
+    last_used_ms: AtomicU64,
+}
+
 struct StandalonePool {
-    pool: DashMap<Endpoint, Arc<StandaloneImpl<RpcClientImpl>>>,
+    inner: Arc<StandalonePoolInner>,
+}
+
+struct StandalonePoolInner {
+    pool: DashMap<Endpoint, PooledStandalone>,
     standalone_buidler: RpcClientImplBuilder,
+    config: StandalonePoolConfig,
+    created_at: Instant,
 }
 
-// TODO better to add gc.
 impl StandalonePool {
-    fn new(standalone_buidler: RpcClientImplBuilder) -> Self {
-        Self {
+    /// Create the pool and spawn its background reaper.
+    ///
+    /// Must be called from within a Tokio runtime: the reaper is started with
+    /// [`tokio::spawn`], which panics if no runtime is active.
+    fn new(standalone_buidler: RpcClientImplBuilder, config: StandalonePoolConfig) -> Self {
+        let inner = Arc::new(StandalonePoolInner {
             pool: DashMap::new(),
             standalone_buidler,
-        }
+            config,
+            created_at: Instant::now(),
+        });
+
+        // Spawn the background reaper holding only a weak reference so the pool
+        // (and hence the client) can be dropped without waiting for the task.
+        let weak = Arc::downgrade(&inner);
+        let gc_interval = inner.config.gc_interval;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(gc_interval);
+            loop {
+                ticker.tick().await;
+                match weak.upgrade() {
+                    Some(inner) => inner.gc_idle(),
+                    None => break,
+                }
+            }
+        });
+
+        Self { inner }
     }
 
     fn get_or_create(&self, endpoint: &Endpoint) -> Arc<StandaloneImpl<RpcClientImpl>> {
-        if let Some(c) = self.pool.get(endpoint) {
-            // If exist in cache, return.
-            c.value().clone()
-        } else {
-            // If not exist, build --> insert --> return.
-            self.pool
-                .entry(endpoint.clone())
-                .or_insert(Arc::new(StandaloneImpl::new(
-                    self.standalone_buidler.build(endpoint.to_string()),
-                )))
-                .clone()
+        self.inner.get_or_create(endpoint)
+    }
+}
+
+impl StandalonePoolInner {
+    fn elapsed_ms(&self) -> u64 {
+        self.created_at.elapsed().as_millis() as u64
+    }
+
+    fn get_or_create(&self, endpoint: &Endpoint) -> Arc<StandaloneImpl<RpcClientImpl>> {
+        let now = self.elapsed_ms();
+
+        if let Some(entry) = self.pool.get(endpoint) {
+            // If exist in cache, refresh the last-used stamp and return.
+            entry.last_used_ms.store(now, Ordering::Relaxed);
+            return entry.client.clone();
         }
+
+        // Keep the pool bounded before inserting a fresh channel.
+        self.enforce_pool_size();
+
+        // If not exist, build --> insert --> return.
+        let entry = self.pool.entry(endpoint.clone()).or_insert_with(|| {
+            let client = Arc::new(StandaloneImpl::new(
+                self.standalone_buidler.build(endpoint.to_string()),
+            ));
+            PooledStandalone {
+                client,
+                last_used_ms: AtomicU64::new(now),
+            }
+        });
+        entry.last_used_ms.store(now, Ordering::Relaxed);
+        entry.client.clone()
+    }
+
+    /// Evict channels that have been idle beyond `max_idle`.
+    ///
+    /// A channel is only dropped when no caller still holds a clone of its
+    /// `Arc` (`strong_count == 1`), so an in-flight request can never lose its
+    /// channel out from under it.
+    fn gc_idle(&self) {
+        let now = self.elapsed_ms();
+        let max_idle = self.config.max_idle.as_millis() as u64;
+        self.pool.retain(|_, entry| {
+            let idle = now.saturating_sub(entry.last_used_ms.load(Ordering::Relaxed));
+            let in_flight = Arc::strong_count(&entry.client) > 1;
+            retain_idle(idle, max_idle, in_flight)
+        });
+    }
+
+    /// Enforce `max_pool_size` by evicting the least-recently-used idle entry.
+    fn enforce_pool_size(&self) {
+        while self.pool.len() >= self.config.max_pool_size {
+            let entries: Vec<(Endpoint, u64, bool)> = self
+                .pool
+                .iter()
+                .map(|entry| {
+                    (
+                        entry.key().clone(),
+                        entry.last_used_ms.load(Ordering::Relaxed),
+                        Arc::strong_count(&entry.client) > 1,
+                    )
+                })
+                .collect();
+
+            let stamps: Vec<(u64, bool)> =
+                entries.iter().map(|(_, last, flight)| (*last, *flight)).collect();
+
+            match lru_victim(&stamps) {
+                // All cached channels are in flight; let the pool overflow
+                // rather than drop a channel still in use.
+                None => break,
+                Some(idx) => {
+                    let endpoint = entries[idx].0.clone();
+                    self.pool
+                        .remove_if(&endpoint, |_, entry| Arc::strong_count(&entry.client) == 1);
+                }
+            }
+        }
+    }
+}
+
+/// Retain predicate for the idle reaper: keep an entry when it is still within
+/// its idle budget, or when it is currently in flight (so an in-flight `Arc` is
+/// never dropped regardless of how long it has been idle).
+fn retain_idle(idle_ms: u64, max_idle_ms: u64, in_flight: bool) -> bool {
+    idle_ms <= max_idle_ms || in_flight
+}
+
+/// Pick the index of the least-recently-used evictable entry from `(last_used,
+/// in_flight)` stamps. In-flight entries are never eligible; `None` means every
+/// entry is in flight.
+fn lru_victim(stamps: &[(u64, bool)]) -> Option<usize> {
+    stamps
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, in_flight))| !in_flight)
+        .min_by_key(|(_, (last_used, _))| *last_used)
+        .map(|(idx, _)| idx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ep(port: u16) -> Endpoint {
+        Endpoint::new("127.0.0.1".to_string(), port)
+    }
+
+    #[test]
+    fn test_select_endpoint_empty() {
+        assert!(select_endpoint(&[], "metric", 0).is_none());
+    }
+
+    #[test]
+    fn test_select_endpoint_deterministic() {
+        let candidates = vec![ep(1), ep(2), ep(3)];
+        // A fixed (metric, rotation) always resolves to the same endpoint.
+        let first = select_endpoint(&candidates, "cpu", 7);
+        assert_eq!(first, select_endpoint(&candidates, "cpu", 7));
+    }
+
+    #[test]
+    fn test_select_endpoint_balances_across_rotations() {
+        let candidates = vec![ep(1), ep(2), ep(3)];
+        let distinct: std::collections::HashSet<_> = (0..64)
+            .filter_map(|rotation| select_endpoint(&candidates, "cpu", rotation))
+            .collect();
+        // Rotating the counter spreads traffic over more than one replica.
+        assert!(distinct.len() > 1);
+    }
+
+    #[test]
+    fn test_sub_write_request_skips_absent_metrics() {
+        // Metrics missing from the original are dropped rather than panicking.
+        let sub = sub_write_request(&WriteRequest::default(), &["ghost".to_string()]);
+        assert!(sub.write_entries.is_empty());
+    }
+
+    #[test]
+    fn test_retain_idle() {
+        // Within budget: kept. Over budget and idle: evicted. Over budget but
+        // in flight: kept.
+        assert!(retain_idle(5, 10, false));
+        assert!(!retain_idle(15, 10, false));
+        assert!(retain_idle(15, 10, true));
+    }
+
+    #[test]
+    fn test_lru_victim() {
+        // The oldest evictable entry is chosen.
+        assert_eq!(lru_victim(&[(30, false), (10, false), (20, false)]), Some(1));
+        // In-flight entries are skipped even if older.
+        assert_eq!(lru_victim(&[(5, true), (50, false)]), Some(1));
+        // Everything in flight: nothing to evict.
+        assert_eq!(lru_victim(&[(5, true), (9, true)]), None);
     }
 }
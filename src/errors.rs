@@ -4,7 +4,7 @@ use std::fmt::Display;
 
 use thiserror::Error as ThisError;
 
-use crate::model::write::WriteResponse;
+use crate::model::write::{WriteRequest, WriteResponse};
 
 #[derive(Debug, ThisError)]
 pub enum Error {
@@ -46,6 +46,16 @@ pub enum Error {
     Unknown(String),
 }
 
+impl Error {
+    /// Whether this error should be retried against a refreshed route.
+    ///
+    /// Only a server error whose code/message pass [`should_refresh`] is
+    /// refreshable; client-side failures such as [`Error::AuthFail`] are not.
+    pub fn is_refreshable(&self) -> bool {
+        matches!(self, Error::Server(serv_err) if should_refresh(serv_err.code, &serv_err.msg))
+    }
+}
+
 #[derive(Debug)]
 pub struct ClusterWriteError {
     pub ok: (Vec<String>, WriteResponse), // (metrics, write_response)
@@ -82,6 +92,38 @@ impl ClusterWriteError {
     pub fn all_ok(&self) -> bool {
         self.errors.is_empty()
     }
+
+    /// Metrics whose failure is refreshable and therefore worth retrying.
+    ///
+    /// Non-refreshable failures (e.g. [`Error::AuthFail`]) are left out so
+    /// callers don't re-send writes that will fail again for the same reason.
+    pub fn retryable_metrics(&self) -> Vec<String> {
+        self.errors
+            .iter()
+            .filter(|(_, e)| e.is_refreshable())
+            .flat_map(|(metrics, _)| metrics.iter().cloned())
+            .collect()
+    }
+
+    /// Reconstruct a [`WriteRequest`] carrying only the entries for the still
+    /// retryable metrics, cloned out of `original`.
+    ///
+    /// Returns `None` when nothing is retryable, so callers can end their
+    /// at-least-once loop without issuing an empty write.
+    pub fn into_retry_request(&self, original: &WriteRequest) -> Option<WriteRequest> {
+        let mut retry = WriteRequest::default();
+        for metric in self.retryable_metrics() {
+            if let Some(entry) = original.write_entries.get(metric.as_str()) {
+                retry.write_entries.insert(metric, entry.clone());
+            }
+        }
+
+        if retry.write_entries.is_empty() {
+            None
+        } else {
+            Some(retry)
+        }
+    }
 }
 
 impl Display for ClusterWriteError {
@@ -150,4 +192,36 @@ mod test {
             r#"failed to connect, addr:"1.1.1.1:1111", err:Unknown("unknown error")"#
         );
     }
+
+    fn auth_fail() -> Error {
+        Error::AuthFail(AuthFailStatus {
+            code: AuthCode::InvalidTokenMeta,
+            msg: "bad token".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_auth_fail_not_retryable() {
+        let cluster_error = ClusterWriteError {
+            ok: (Vec::new(), WriteResponse::new(0, 0)),
+            errors: vec![(vec!["m1".to_string()], auth_fail())],
+        };
+        // Non-refreshable failures must never be offered for retry.
+        assert!(cluster_error.retryable_metrics().is_empty());
+        assert!(cluster_error
+            .into_retry_request(&WriteRequest::default())
+            .is_none());
+    }
+
+    #[test]
+    fn test_into_retry_request_none_when_all_ok() {
+        let cluster_error = ClusterWriteError {
+            ok: (vec!["m1".to_string()], WriteResponse::new(1, 0)),
+            errors: Vec::new(),
+        };
+        assert!(cluster_error.all_ok());
+        assert!(cluster_error
+            .into_retry_request(&WriteRequest::default())
+            .is_none());
+    }
 }
@@ -0,0 +1,204 @@
+// Copyright 2022 CeresDB Project Authors. Licensed under Apache-2.0.
+
+//! A [`Router`] backed by a Consul service catalog.
+//!
+//! Instead of fetching a route table over RPC, this router discovers CeresDB
+//! endpoints from Consul's HTTP health API and refreshes them on a fixed
+//! interval. It is modeled on the node-discovery loop Garage drives against
+//! Consul, adapted to the [`Router`] trait so `ClusterImpl` can be built with
+//! either router without any other changes.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::{
+    model::route::Endpoint,
+    router::Router,
+    rpc_client::RpcContext,
+    Error, Result,
+};
+
+/// Default interval between two Consul catalog polls.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Decides whether a discovered service entry should be routed to. This runs on
+/// top of Consul's own passing-health filter, e.g. to additionally match tags.
+pub type HealthFilter = Arc<dyn Fn(&ServiceEntry) -> bool + Send + Sync>;
+
+/// Configuration for [`ConsulRouter`].
+#[derive(Clone)]
+pub struct ConsulRouterConfig {
+    /// Base address of the Consul agent HTTP API, e.g. `http://127.0.0.1:8500`.
+    pub consul_addr: String,
+    /// Name of the CeresDB service registered in Consul.
+    pub service_name: String,
+    /// How often the catalog is re-polled.
+    pub refresh_interval: Duration,
+    /// Keeps only the nodes for which this returns `true`. Defaults to a filter
+    /// that accepts every node.
+    pub health_filter: HealthFilter,
+}
+
+impl ConsulRouterConfig {
+    pub fn new(consul_addr: String, service_name: String) -> Self {
+        Self {
+            consul_addr,
+            service_name,
+            refresh_interval: DEFAULT_REFRESH_INTERVAL,
+            health_filter: Arc::new(|_| true),
+        }
+    }
+}
+
+/// A single entry of the `/v1/health/service/<name>` response, carrying the
+/// node/service registration. The entry's health checks are not deserialized.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceEntry {
+    #[serde(rename = "Node")]
+    pub node: Node,
+    #[serde(rename = "Service")]
+    pub service: Service,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Node {
+    #[serde(rename = "Address")]
+    pub address: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Service {
+    #[serde(rename = "Address")]
+    pub address: String,
+    #[serde(rename = "Port")]
+    pub port: u16,
+}
+
+impl ServiceEntry {
+    /// The address to route to, preferring the service-specific address and
+    /// falling back to the node address when it is empty.
+    fn endpoint(&self) -> Endpoint {
+        let addr = if self.service.address.is_empty() {
+            self.node.address.clone()
+        } else {
+            self.service.address.clone()
+        };
+        Endpoint::new(addr, self.service.port)
+    }
+}
+
+/// [`Router`] discovering endpoints from a Consul service catalog.
+pub struct ConsulRouter {
+    inner: Arc<ConsulRouterInner>,
+}
+
+struct ConsulRouterInner {
+    config: ConsulRouterConfig,
+    http: reqwest::Client,
+    /// Endpoints of the currently healthy service nodes.
+    endpoints: RwLock<Vec<Endpoint>>,
+    /// Set by `evict` so the next `route` call polls Consul before answering.
+    needs_refresh: AtomicBool,
+}
+
+impl ConsulRouter {
+    /// Build the router and spawn the background poller.
+    ///
+    /// Must be called from within a Tokio runtime: the poller is started with
+    /// [`tokio::spawn`], which panics if no runtime is active.
+    pub fn new(config: ConsulRouterConfig) -> Self {
+        let inner = Arc::new(ConsulRouterInner {
+            config,
+            http: reqwest::Client::new(),
+            endpoints: RwLock::new(Vec::new()),
+            needs_refresh: AtomicBool::new(true),
+        });
+
+        let weak = Arc::downgrade(&inner);
+        let refresh_interval = inner.config.refresh_interval;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(refresh_interval);
+            loop {
+                ticker.tick().await;
+                match weak.upgrade() {
+                    Some(inner) => {
+                        if let Err(e) = inner.poll().await {
+                            // A transient Consul failure keeps the last known
+                            // endpoints; the next tick will retry.
+                            log::warn!("failed to poll consul catalog, err:{e}");
+                        }
+                    }
+                    None => break,
+                }
+            }
+        });
+
+        Self { inner }
+    }
+}
+
+impl ConsulRouterInner {
+    /// Fetch the passing-health service entries and replace the endpoint cache.
+    ///
+    /// `?passing` makes Consul return only entries whose health checks are all
+    /// passing; the configured [`HealthFilter`] can narrow the set further.
+    async fn poll(&self) -> Result<()> {
+        let url = format!(
+            "{}/v1/health/service/{}?passing",
+            self.config.consul_addr.trim_end_matches('/'),
+            self.config.service_name
+        );
+
+        let entries: Vec<ServiceEntry> = self
+            .http
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| Error::Client(format!("failed to query consul, err:{e}")))?
+            .json()
+            .await
+            .map_err(|e| Error::Client(format!("failed to decode consul response, err:{e}")))?;
+
+        let endpoints: Vec<Endpoint> = entries
+            .into_iter()
+            .filter(|entry| (self.config.health_filter)(entry))
+            .map(|entry| entry.endpoint())
+            .collect();
+
+        *self.endpoints.write().await = endpoints;
+        self.needs_refresh.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Router for ConsulRouter {
+    async fn route(&self, metrics: &[String], _ctx: &RpcContext) -> Result<Vec<Vec<Endpoint>>> {
+        // A forced refresh was requested via `evict`; serve it synchronously so
+        // the caller routes against fresh membership instead of waiting a tick.
+        if self.inner.needs_refresh.swap(false, Ordering::Relaxed) {
+            self.inner.poll().await?;
+        }
+
+        // Every metric is servable by any discovered node, so each gets the
+        // full candidate set; the cluster client load-balances across them.
+        let endpoints = self.inner.endpoints.read().await;
+        Ok(metrics.iter().map(|_| endpoints.clone()).collect())
+    }
+
+    fn evict(&self, _metrics: &[String]) {
+        // Discovery is service-wide rather than per-metric, so any eviction
+        // simply makes the next `route` call re-read the service list.
+        self.inner.needs_refresh.store(true, Ordering::Relaxed);
+    }
+}